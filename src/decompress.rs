@@ -0,0 +1,212 @@
+#[cfg(feature = "brotli")]
+use async_compression::futures::bufread::BrotliDecoder;
+#[cfg(feature = "deflate")]
+use async_compression::futures::bufread::DeflateDecoder;
+#[cfg(feature = "gzip")]
+use async_compression::futures::bufread::GzipDecoder;
+#[cfg(feature = "zstd")]
+use async_compression::futures::bufread::ZstdDecoder;
+use futures_lite::io::BufReader;
+use tide::http::content::{ContentEncoding, Encoding};
+use tide::http::{headers, Body, StatusCode};
+use tide::{Middleware, Next, Request, Response};
+
+use crate::encoding::encoding_from_str;
+
+/// A middleware for decompressing request body data.
+///
+/// ## Example
+/// ```rust
+/// # async_std::task::block_on(async {
+/// let mut app = tide::new();
+///
+/// app.with(tide_compress::DecompressMiddleware::new());
+/// # })
+/// ```
+#[derive(Clone, Debug)]
+pub struct DecompressMiddleware;
+
+impl Default for DecompressMiddleware {
+    fn default() -> Self {
+        Self
+    }
+}
+
+impl DecompressMiddleware {
+    /// Creates a new DecompressMiddleware.
+    ///
+    /// ## Example
+    /// ```rust
+    /// # async_std::task::block_on(async {
+    /// let mut app = tide::new();
+    ///
+    /// app.with(tide_compress::DecompressMiddleware::new());
+    /// # })
+    /// ```
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for DecompressMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let content_encoding = ContentEncoding::from_headers(&req)?;
+
+        // Can't tell if we should decompress if there's no Content-Encoding header, and
+        // there's nothing to undo if the body is already in its identity encoding.
+        if let Some(content_encoding) = content_encoding {
+            if content_encoding != Encoding::Identity {
+                if let Some(header) = req.header(&headers::CONTENT_ENCODING) {
+                    let tokens: Vec<&str> = header
+                        .iter()
+                        .flat_map(|value| value.as_str().split(','))
+                        .map(str::trim)
+                        .collect();
+
+                    // Every listed token must name a codec we can actually decode before we
+                    // touch the body or headers. Otherwise we'd strip Content-Encoding from a
+                    // request whose body is still (partly) compressed, handing the handler
+                    // garbage that looks like identity-encoded data.
+                    let mut encodings = Vec::with_capacity(tokens.len());
+
+                    for token in tokens {
+                        match encoding_from_str(token) {
+                            Some(encoding) => encodings.push(encoding),
+                            None => return Ok(Response::new(StatusCode::UnsupportedMediaType)),
+                        }
+                    }
+
+                    let mut body = req.take_body();
+
+                    // A chained Content-Encoding (e.g. "gzip, br") lists encodings in the order
+                    // they were applied, so the decoders are applied in reverse: the last-listed
+                    // (outermost) encoding is undone first.
+                    for encoding in encodings.iter().rev() {
+                        body = decode(body, encoding);
+                    }
+
+                    req.set_body(body);
+
+                    // Body length and shape no longer match what the client sent.
+                    req.remove_header(headers::CONTENT_ENCODING);
+                    req.remove_header(headers::CONTENT_LENGTH);
+                }
+            }
+        }
+
+        Ok(next.run(req).await)
+    }
+}
+
+/// Returns a `Body` decoded with the decoder matching `encoding`.
+///
+/// `encoding` is assumed to be one `encoding_from_str` would actually recognize (`Identity` or a
+/// codec compiled into this build); `handle` never calls this with anything else.
+fn decode(body: Body, encoding: &Encoding) -> Body {
+    if *encoding == Encoding::Identity {
+        return body;
+    }
+
+    #[cfg(feature = "zstd")]
+    {
+        if *encoding == Encoding::Zstd {
+            return Body::from_reader(BufReader::new(ZstdDecoder::new(body)), None);
+        }
+    }
+
+    #[cfg(feature = "brotli")]
+    {
+        if *encoding == Encoding::Brotli {
+            return Body::from_reader(BufReader::new(BrotliDecoder::new(body)), None);
+        }
+    }
+
+    #[cfg(feature = "gzip")]
+    {
+        if *encoding == Encoding::Gzip {
+            return Body::from_reader(BufReader::new(GzipDecoder::new(body)), None);
+        }
+    }
+
+    #[cfg(feature = "deflate")]
+    {
+        if *encoding == Encoding::Deflate {
+            return Body::from_reader(BufReader::new(DeflateDecoder::new(body)), None);
+        }
+    }
+
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_lite::AsyncReadExt;
+
+    async fn drain(mut body: Body) -> Vec<u8> {
+        let mut buf = Vec::new();
+        body.read_to_end(&mut buf).await.unwrap();
+        buf
+    }
+
+    #[cfg(feature = "gzip")]
+    #[async_std::test]
+    async fn decode_round_trips_a_gzip_body() {
+        use async_compression::futures::bufread::GzipEncoder;
+
+        let raw = b"hello world, decompressed via gzip".to_vec();
+        let compressed = drain(Body::from_reader(
+            BufReader::new(GzipEncoder::new(BufReader::new(Body::from_bytes(
+                raw.clone(),
+            )))),
+            None,
+        ))
+        .await;
+
+        let decoded = decode(Body::from_bytes(compressed), &Encoding::Gzip);
+        assert_eq!(drain(decoded).await, raw);
+    }
+
+    #[cfg(all(feature = "gzip", feature = "brotli"))]
+    #[async_std::test]
+    async fn decode_undoes_a_chained_encoding_in_reverse_order() {
+        use async_compression::futures::bufread::{BrotliEncoder, GzipEncoder};
+
+        let raw = b"hello world, wrapped in gzip then brotli".to_vec();
+
+        // `Content-Encoding: gzip, br` means gzip was applied first and br applied on top, so a
+        // compliant client expects us to undo br before gzip.
+        let gzipped = drain(Body::from_reader(
+            BufReader::new(GzipEncoder::new(BufReader::new(Body::from_bytes(
+                raw.clone(),
+            )))),
+            None,
+        ))
+        .await;
+        let compressed = drain(Body::from_reader(
+            BufReader::new(BrotliEncoder::new(BufReader::new(Body::from_bytes(
+                gzipped,
+            )))),
+            None,
+        ))
+        .await;
+
+        let mut body = Body::from_bytes(compressed);
+
+        for encoding in [Encoding::Gzip, Encoding::Brotli].iter().rev() {
+            body = decode(body, encoding);
+        }
+
+        assert_eq!(drain(body).await, raw);
+    }
+
+    #[async_std::test]
+    async fn decode_leaves_identity_untouched() {
+        // `decode` never receives anything `encoding_from_str` wouldn't recognize, but
+        // `Identity` can legitimately appear in a chain (e.g. a no-op link) and must be a no-op.
+        let body = decode(Body::from_bytes(b"untouched".to_vec()), &Encoding::Identity);
+
+        assert_eq!(drain(body).await, b"untouched");
+    }
+}
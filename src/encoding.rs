@@ -0,0 +1,56 @@
+use tide::http::content::Encoding;
+
+/// The codecs compiled into this build, in server-preference order (highest first). Kept in one
+/// place so the negotiation and (de)compression paths can't drift on which encodings are
+/// actually available.
+pub(crate) const SUPPORTED_ENCODINGS: &[Encoding] = &[
+    #[cfg(feature = "zstd")]
+    Encoding::Zstd,
+    #[cfg(feature = "brotli")]
+    Encoding::Brotli,
+    #[cfg(feature = "gzip")]
+    Encoding::Gzip,
+    #[cfg(feature = "deflate")]
+    Encoding::Deflate,
+];
+
+/// Parses a single encoding token (e.g. `gzip`, `br`) into its `Encoding`, recognizing `identity`
+/// and whichever codecs are compiled into this build.
+///
+/// `Encoding` has no public `FromStr` impl, so we mirror the token spelling it renders via
+/// `Display` ourselves. A token naming a codec that exists but isn't compiled in (its feature is
+/// disabled) is indistinguishable here from an unrecognized token; callers that need to tell
+/// those apart shouldn't rely on this helper for that distinction.
+pub(crate) fn encoding_from_str(token: &str) -> Option<Encoding> {
+    if token == "identity" {
+        return Some(Encoding::Identity);
+    }
+
+    SUPPORTED_ENCODINGS
+        .iter()
+        .copied()
+        .find(|encoding| encoding.to_string() == token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_identity() {
+        assert_eq!(encoding_from_str("identity"), Some(Encoding::Identity));
+    }
+
+    #[test]
+    fn recognizes_compiled_in_codecs() {
+        for &encoding in SUPPORTED_ENCODINGS {
+            assert_eq!(encoding_from_str(&encoding.to_string()), Some(encoding));
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_tokens() {
+        assert_eq!(encoding_from_str("bogus"), None);
+        assert_eq!(encoding_from_str(""), None);
+    }
+}
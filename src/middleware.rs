@@ -1,19 +1,107 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
 #[cfg(feature = "brotli")]
 use async_compression::futures::bufread::BrotliEncoder;
 #[cfg(feature = "deflate")]
 use async_compression::futures::bufread::DeflateEncoder;
 #[cfg(feature = "gzip")]
 use async_compression::futures::bufread::GzipEncoder;
+#[cfg(feature = "zstd")]
+use async_compression::futures::bufread::ZstdEncoder;
 use async_compression::Level;
 use futures_lite::io::BufReader;
 use tide::http::cache::{CacheControl, CacheDirective};
 use tide::http::conditional::Vary;
-use tide::http::content::{AcceptEncoding, ContentEncoding, Encoding};
-use tide::http::{headers, Body, Method};
+use tide::http::content::{ContentEncoding, Encoding};
+use tide::http::{headers, Body, Method, Mime};
 use tide::{Middleware, Next, Request, Response};
 
+use crate::encoding::{encoding_from_str, SUPPORTED_ENCODINGS};
+
 const THRESHOLD: usize = 1024;
 
+/// A bounded cache mapping raw `Accept-Encoding` header values to their already-negotiated
+/// `Encoding` decision, evicting the least-recently-used entry once `capacity` is exceeded.
+#[derive(Debug)]
+struct NegotiationCache {
+    capacity: usize,
+    entries: HashMap<String, Encoding>,
+    order: VecDeque<String>,
+}
+
+impl NegotiationCache {
+    fn new(capacity: usize) -> Self {
+        NegotiationCache {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Returns the cached decision for `header`, marking it most-recently-used.
+    fn get(&mut self, header: &str) -> Option<Encoding> {
+        let encoding = *self.entries.get(header)?;
+
+        self.order.retain(|cached| cached != header);
+        self.order.push_back(header.to_owned());
+
+        Some(encoding)
+    }
+
+    /// Records the decision for `header`, evicting the least-recently-used entry if needed.
+    fn insert(&mut self, header: String, encoding: Encoding) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let is_new = self.entries.insert(header.clone(), encoding).is_none();
+
+        if is_new && self.entries.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.order.retain(|cached| cached != &header);
+        self.order.push_back(header);
+    }
+}
+
+/// Returns the default set of media types that are skipped by compression because they're
+/// already compressed (or otherwise unlikely to shrink further).
+///
+/// `application/octet-stream` is deliberately not included: it's the mime a `Body` defaults to
+/// when constructed without an explicit content type (e.g. `Body::from_reader(.., None)`), so
+/// excluding it would silently stop compressing any streamed response that doesn't set
+/// `Content-Type`.
+fn default_exclusions() -> Vec<Mime> {
+    [
+        "image/*",
+        "video/*",
+        "audio/*",
+        "application/gzip",
+        "application/zip",
+        "font/woff2",
+    ]
+    .iter()
+    .map(|mime| mime.parse().expect("default exclusion is a valid mime"))
+    .collect()
+}
+
+/// Returns whether `content_type` matches one of the `exclusions`.
+///
+/// An exclusion with a `*` subtype (e.g. `image/*`) matches any subtype sharing its basetype.
+fn is_excluded(content_type: &Mime, exclusions: &[Mime]) -> bool {
+    exclusions.iter().any(|exclusion| {
+        if exclusion.subtype() == "*" {
+            exclusion.basetype() == content_type.basetype()
+        } else {
+            exclusion.essence() == content_type.essence()
+        }
+    })
+}
+
 /// The configured compression level for all available compression algorithms.
 ///
 /// ## Example
@@ -25,9 +113,10 @@ const THRESHOLD: usize = 1024;
 ///     brotli: tide_compress::Level::Precise(4),
 ///     gzip: tide_compress::Level::Fastest,
 ///     deflate: tide_compress::Level::Default,
+///     zstd: tide_compress::Level::Default,
 /// };
 ///
-/// app.with(tide_compress::CompressMiddleware::with_levels(levels));
+/// app.with(tide_compress::CompressMiddleware::new().with_levels(levels));
 /// # })
 /// ```
 #[derive(Clone, Debug)]
@@ -38,6 +127,8 @@ pub struct CompressionLevels {
     pub gzip: Level,
     #[cfg(feature = "deflate")]
     pub deflate: Level,
+    #[cfg(feature = "zstd")]
+    pub zstd: Level,
 }
 
 impl Default for CompressionLevels {
@@ -49,6 +140,8 @@ impl Default for CompressionLevels {
             gzip: Level::Default,
             #[cfg(feature = "deflate")]
             deflate: Level::Default,
+            #[cfg(feature = "zstd")]
+            zstd: Level::Default,
         }
     }
 }
@@ -64,7 +157,7 @@ impl CompressionLevels {
     ///
     /// let levels = tide_compress::CompressionLevels::all(tide_compress::Level::Fastest);
     ///
-    /// app.with(tide_compress::CompressMiddleware::with_levels(levels));
+    /// app.with(tide_compress::CompressMiddleware::new().with_levels(levels));
     /// # })
     /// ```
     pub fn all(level: Level) -> Self {
@@ -75,6 +168,8 @@ impl CompressionLevels {
             gzip: level,
             #[cfg(feature = "deflate")]
             deflate: level,
+            #[cfg(feature = "zstd")]
+            zstd: level,
         }
     }
 }
@@ -93,6 +188,8 @@ impl CompressionLevels {
 pub struct CompressMiddleware {
     threshold: usize,
     levels: CompressionLevels,
+    exclusions: Vec<Mime>,
+    negotiation_cache: Option<Arc<Mutex<NegotiationCache>>>,
 }
 
 impl Default for CompressMiddleware {
@@ -100,6 +197,8 @@ impl Default for CompressMiddleware {
         CompressMiddleware {
             threshold: THRESHOLD,
             levels: CompressionLevels::default(),
+            exclusions: default_exclusions(),
+            negotiation_cache: None,
         }
     }
 }
@@ -107,7 +206,9 @@ impl Default for CompressMiddleware {
 impl CompressMiddleware {
     /// Creates a new CompressMiddleware.
     ///
-    /// Uses the default minimum body size threshold (1024 bytes) and compression levels.
+    /// Uses the default minimum body size threshold (1024 bytes), compression levels, and
+    /// exclusion list, with the negotiation cache disabled. Chain the `with_*` builder methods
+    /// to customize any of these.
     ///
     /// ## Example
     /// ```rust
@@ -121,9 +222,7 @@ impl CompressMiddleware {
         Self::default()
     }
 
-    /// Creates a new CompressMiddleware with a custom minimum body size threshold.
-    ///
-    /// Uses the default compression levels.
+    /// Sets a custom minimum body size threshold.
     ///
     /// # Arguments
     ///
@@ -134,19 +233,15 @@ impl CompressMiddleware {
     /// # async_std::task::block_on(async {
     /// let mut app = tide::new();
     ///
-    /// app.with(tide_compress::CompressMiddleware::with_threshold(512));
+    /// app.with(tide_compress::CompressMiddleware::new().with_threshold(512));
     /// # })
     /// ```
-    pub fn with_threshold(threshold: usize) -> Self {
-        CompressMiddleware {
-            threshold,
-            ..CompressMiddleware::default()
-        }
+    pub fn with_threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
     }
 
-    /// Creates a new CompressMiddleware with custom compression levels.
-    ///
-    /// Uses the default minimum body size threshold (1024 bytes).
+    /// Sets custom compression levels.
     ///
     /// # Arguments
     ///
@@ -161,42 +256,87 @@ impl CompressMiddleware {
     ///     brotli: tide_compress::Level::Precise(4),
     ///     gzip: tide_compress::Level::Fastest,
     ///     deflate: tide_compress::Level::Default,
+    ///     zstd: tide_compress::Level::Default,
     /// };
     ///
-    /// app.with(tide_compress::CompressMiddleware::with_levels(levels));
+    /// app.with(tide_compress::CompressMiddleware::new().with_levels(levels));
     /// # })
     /// ```
-    pub fn with_levels(levels: CompressionLevels) -> Self {
-        CompressMiddleware {
-            levels,
-            ..CompressMiddleware::default()
-        }
+    pub fn with_levels(mut self, levels: CompressionLevels) -> Self {
+        self.levels = levels;
+        self
     }
 
-    /// Creates a new CompressMiddleware with a custom minimum body size threshold and compression
-    /// levels.
+    /// Sets a custom set of excluded media types.
+    ///
+    /// Responses whose `Content-Type` matches one of the `exclusions` are left uncompressed,
+    /// regardless of size. This replaces the default exclusion list entirely; include any of
+    /// its entries you still want to keep.
     ///
     /// # Arguments
     ///
-    /// * `threshold` - minimum body size in bytes.
-    /// * `levels` - desired compression levels.
+    /// * `exclusions` - media types to skip compressing, e.g. already-compressed formats.
     ///
     /// ## Example
     /// ```rust
     /// # async_std::task::block_on(async {
     /// let mut app = tide::new();
     ///
-    /// let levels = tide_compress::CompressionLevels {
-    ///     brotli: tide_compress::Level::Precise(4),
-    ///     gzip: tide_compress::Level::Fastest,
-    ///     deflate: tide_compress::Level::Default,
-    /// };
+    /// let exclusions: Vec<tide::http::Mime> = vec!["application/pdf".parse().unwrap()];
     ///
-    /// app.with(tide_compress::CompressMiddleware::with_threshold_and_levels(512, levels));
+    /// app.with(tide_compress::CompressMiddleware::new().with_exclusions(exclusions));
     /// # })
     /// ```
-    pub fn with_threshold_and_levels(threshold: usize, levels: CompressionLevels) -> Self {
-        CompressMiddleware { threshold, levels }
+    pub fn with_exclusions(mut self, exclusions: Vec<Mime>) -> Self {
+        self.exclusions = exclusions;
+        self
+    }
+
+    /// Enables a bounded cache of negotiated `Accept-Encoding` decisions, keyed by the raw
+    /// header value.
+    ///
+    /// Real clients send a small, repeating set of `Accept-Encoding` values, so caching the
+    /// negotiation outcome (including "do not compress") avoids re-parsing and re-negotiating on
+    /// every request. The cache is disabled by default; pass a `capacity` of 0 to accept requests
+    /// without ever caching.
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - maximum number of distinct `Accept-Encoding` values to remember.
+    ///
+    /// ## Example
+    /// ```rust
+    /// # async_std::task::block_on(async {
+    /// let mut app = tide::new();
+    ///
+    /// app.with(tide_compress::CompressMiddleware::new().with_negotiation_cache(128));
+    /// # })
+    /// ```
+    pub fn with_negotiation_cache(mut self, capacity: usize) -> Self {
+        self.negotiation_cache = Some(Arc::new(Mutex::new(NegotiationCache::new(capacity))));
+        self
+    }
+
+    /// Negotiates the `Encoding` for a raw `Accept-Encoding` header value, consulting (and
+    /// populating) the negotiation cache if one is configured.
+    fn negotiate_cached(&self, header: &str) -> Encoding {
+        let cache = match &self.negotiation_cache {
+            Some(cache) => cache,
+            None => return negotiate_quality(header, SUPPORTED_ENCODINGS),
+        };
+
+        let mut cache = cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if let Some(encoding) = cache.get(header) {
+            return encoding;
+        }
+
+        let encoding = negotiate_quality(header, SUPPORTED_ENCODINGS);
+        cache.insert(header.to_owned(), encoding);
+
+        encoding
     }
 }
 
@@ -206,17 +346,18 @@ impl<State: Clone + Send + Sync + 'static> Middleware<State> for CompressMiddlew
         // Incoming Request data
         // Need to grab these things before the request is consumed by `next.run()`.
         let is_head = req.method() == Method::Head;
-        let accepts = AcceptEncoding::from_headers(&req)?;
+        let raw_accept_encoding = req
+            .header(&headers::ACCEPT_ENCODING)
+            .map(ToString::to_string);
 
         // Propagate to route
         let mut res: Response = next.run(req).await;
 
         // Head requests should have no body to compress.
-        // Can't tell if we can compress if there is no Accepts-Encoding header.
-        if is_head || accepts.is_none() {
+        // Can't tell if we can compress if there is no Accept-Encoding header.
+        if is_head || raw_accept_encoding.is_none() {
             return Ok(res);
         }
-        let mut accepts = accepts.unwrap();
 
         // Should we transform?
         if let Some(cache_control) = CacheControl::from_headers(&res)? {
@@ -250,15 +391,27 @@ impl<State: Clone + Send + Sync + 'static> Middleware<State> for CompressMiddlew
             }
         }
 
+        // Skip media types that are already compressed (images, video, archives, ...).
+        if let Some(content_type) = res.content_type() {
+            if is_excluded(&content_type, &self.exclusions) {
+                return Ok(res);
+            }
+        }
+
+        // Resolve the client's weighted preferences (honouring `q=0` rejections and the `*`
+        // wildcard) against our server-preference order, falling back to identity if nothing
+        // acceptable remains rather than erroring out.
+        //
+        // `raw_accept_encoding` is guaranteed `Some` here: the early return above already
+        // bailed out when there was no Accept-Encoding header to negotiate against.
+        let chosen = self.negotiate_cached(raw_accept_encoding.as_deref().unwrap());
+
+        if chosen == Encoding::Identity {
+            return Ok(res);
+        }
+
+        let encoding = ContentEncoding::new(chosen);
         let body = res.take_body();
-        let encoding = accepts.negotiate(&[
-            #[cfg(feature = "brotli")]
-            Encoding::Brotli,
-            #[cfg(feature = "gzip")]
-            Encoding::Gzip,
-            #[cfg(feature = "deflate")]
-            Encoding::Deflate,
-        ])?;
 
         // Get a new Body backed by an appropriate encoder, if one is available.
         res.set_body(get_encoder(body, &encoding, &self.levels));
@@ -271,8 +424,99 @@ impl<State: Clone + Send + Sync + 'static> Middleware<State> for CompressMiddlew
     }
 }
 
+/// A single token from an `Accept-Encoding` header, with its quality value. `encoding` is `None`
+/// for the `*` wildcard.
+struct EncodingPreference {
+    encoding: Option<Encoding>,
+    quality: f32,
+}
+
+/// Parses a raw `Accept-Encoding` header value into its individual weighted preferences.
+///
+/// Unparseable tokens (encodings we don't recognize) are dropped, as if they were never sent.
+fn parse_preferences(header: &str) -> Vec<EncodingPreference> {
+    header
+        .split(',')
+        .filter_map(|part| {
+            let mut segments = part.split(';').map(str::trim);
+            let token = segments.next()?;
+
+            let quality = segments
+                .find_map(|segment| segment.strip_prefix("q="))
+                .and_then(|value| value.trim().parse().ok())
+                .unwrap_or(1.0);
+
+            let encoding = if token == "*" {
+                None
+            } else {
+                Some(encoding_from_str(token)?)
+            };
+
+            Some(EncodingPreference { encoding, quality })
+        })
+        .collect()
+}
+
+/// Picks the best encoding from `server_order` (given in server-preference order) according to
+/// the client's weighted `Accept-Encoding` header, or `Encoding::Identity` if nothing in
+/// `server_order` is acceptable.
+///
+/// A `q=0` preference is a hard rejection. The `*` wildcard, if present and not itself rejected,
+/// is expanded to cover only the single most-preferred `server_order` entry the client didn't
+/// mention explicitly. Ties in quality fall back to `server_order`.
+fn negotiate_quality(header: &str, server_order: &[Encoding]) -> Encoding {
+    let preferences = parse_preferences(header);
+    let wildcard_quality = preferences
+        .iter()
+        .find(|preference| preference.encoding.is_none())
+        .map(|preference| preference.quality);
+
+    let mut wildcard_spent = false;
+    let mut best: Option<(Encoding, f32)> = None;
+
+    for &encoding in server_order {
+        let explicit = preferences
+            .iter()
+            .filter(|preference| preference.encoding == Some(encoding))
+            .map(|preference| preference.quality)
+            .fold(None, |max: Option<f32>, quality| {
+                Some(max.map_or(quality, |max| max.max(quality)))
+            });
+
+        let quality = match explicit {
+            Some(quality) => quality,
+            None if !wildcard_spent => {
+                wildcard_spent = true;
+                wildcard_quality.unwrap_or(0.0)
+            }
+            None => 0.0,
+        };
+
+        let improves_on_best = match best {
+            Some((_, best_quality)) => quality > best_quality,
+            None => true,
+        };
+
+        if quality > 0.0 && improves_on_best {
+            best = Some((encoding, quality));
+        }
+    }
+
+    best.map_or(Encoding::Identity, |(encoding, _)| encoding)
+}
+
 /// Returns a `Body` made from an encoder chosen from the `Encoding`.
 fn get_encoder(body: Body, encoding: &ContentEncoding, levels: &CompressionLevels) -> Body {
+    #[cfg(feature = "zstd")]
+    {
+        if *encoding == Encoding::Zstd {
+            return Body::from_reader(
+                BufReader::new(ZstdEncoder::with_quality(body, levels.zstd)),
+                None,
+            );
+        }
+    }
+
     #[cfg(feature = "brotli")]
     {
         if *encoding == Encoding::Brotli {
@@ -305,3 +549,129 @@ fn get_encoder(body: Body, encoding: &ContentEncoding, levels: &CompressionLevel
 
     body
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_exclusions_does_not_panic() {
+        // `CompressMiddleware::new()` builds the default exclusion list from string literals;
+        // this exercises that path so a typo'd literal fails the test suite instead of every
+        // caller's first `CompressMiddleware::new()`.
+        let _ = CompressMiddleware::new();
+    }
+
+    #[test]
+    fn octet_stream_is_not_excluded_by_default() {
+        let exclusions = default_exclusions();
+        let octet_stream: Mime = "application/octet-stream".parse().unwrap();
+
+        assert!(!is_excluded(&octet_stream, &exclusions));
+    }
+
+    #[test]
+    fn wildcard_exclusion_matches_any_subtype() {
+        let exclusions = default_exclusions();
+        let jpeg: Mime = "image/jpeg".parse().unwrap();
+        let png: Mime = "image/png".parse().unwrap();
+
+        assert!(is_excluded(&jpeg, &exclusions));
+        assert!(is_excluded(&png, &exclusions));
+    }
+
+    #[test]
+    fn exact_exclusion_does_not_match_other_subtypes() {
+        let exclusions = default_exclusions();
+        let pdf: Mime = "application/pdf".parse().unwrap();
+
+        assert!(!is_excluded(&pdf, &exclusions));
+    }
+
+    // A fixed, feature-independent preference order for exercising `negotiate_quality` without
+    // depending on which codec features happen to be compiled in.
+    const ORDER: &[Encoding] = &[Encoding::Brotli, Encoding::Gzip];
+
+    #[test]
+    fn q_zero_is_a_hard_rejection() {
+        assert_eq!(
+            negotiate_quality("br;q=0, gzip;q=0.5", ORDER),
+            Encoding::Gzip
+        );
+    }
+
+    #[test]
+    fn wildcard_expands_to_most_preferred_unmentioned_encoding() {
+        // The client explicitly deprioritizes gzip but leaves brotli to the wildcard, which
+        // should cover our single most-preferred remaining codec (brotli) regardless of gzip's
+        // low explicit weight.
+        assert_eq!(
+            negotiate_quality("gzip;q=0.1, *;q=0.9", ORDER),
+            Encoding::Brotli
+        );
+    }
+
+    #[test]
+    fn ties_fall_back_to_server_preference_order() {
+        assert_eq!(
+            negotiate_quality("br;q=0.5, gzip;q=0.5", ORDER),
+            Encoding::Brotli
+        );
+    }
+
+    #[test]
+    fn nothing_acceptable_falls_back_to_identity() {
+        assert_eq!(
+            negotiate_quality("br;q=0, gzip;q=0", ORDER),
+            Encoding::Identity
+        );
+    }
+
+    #[test]
+    fn negotiation_cache_hits_on_a_repeated_header() {
+        let mut cache = NegotiationCache::new(2);
+
+        assert_eq!(cache.get("gzip"), None);
+        cache.insert("gzip".to_owned(), Encoding::Gzip);
+
+        assert_eq!(cache.get("gzip"), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn negotiation_cache_evicts_the_least_recently_used_entry() {
+        let mut cache = NegotiationCache::new(2);
+
+        cache.insert("gzip".to_owned(), Encoding::Gzip);
+        cache.insert("br".to_owned(), Encoding::Brotli);
+
+        // Touch "gzip" so "br" becomes the least-recently-used entry.
+        assert_eq!(cache.get("gzip"), Some(Encoding::Gzip));
+
+        cache.insert("deflate".to_owned(), Encoding::Deflate);
+
+        assert_eq!(cache.get("br"), None);
+        assert_eq!(cache.get("gzip"), Some(Encoding::Gzip));
+        assert_eq!(cache.get("deflate"), Some(Encoding::Deflate));
+    }
+
+    #[test]
+    fn negotiation_cache_with_zero_capacity_never_caches() {
+        let mut cache = NegotiationCache::new(0);
+
+        cache.insert("gzip".to_owned(), Encoding::Gzip);
+
+        assert_eq!(cache.get("gzip"), None);
+    }
+
+    #[test]
+    fn builder_methods_compose() {
+        let middleware = CompressMiddleware::new()
+            .with_threshold(512)
+            .with_exclusions(vec!["application/pdf".parse().unwrap()])
+            .with_negotiation_cache(64);
+
+        assert_eq!(middleware.threshold, 512);
+        assert_eq!(middleware.exclusions.len(), 1);
+        assert!(middleware.negotiation_cache.is_some());
+    }
+}
@@ -6,6 +6,7 @@ async fn all() {
             brotli: tide_compress::Level::Fastest,
             deflate: tide_compress::Level::Fastest,
             gzip: tide_compress::Level::Fastest,
+            zstd: tide_compress::Level::Fastest,
         },
     ));
 
@@ -15,6 +16,7 @@ async fn all() {
             brotli: tide_compress::Level::Best,
             deflate: tide_compress::Level::Best,
             gzip: tide_compress::Level::Best,
+            zstd: tide_compress::Level::Best,
         },
     ));
 }